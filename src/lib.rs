@@ -1,4 +1,3 @@
-use anyhow::Result;
 use spin_sdk::{
     http::{Request, Response},
     http_component,
@@ -6,6 +5,40 @@ use spin_sdk::{
 
 use libsql_client::{args, spin::Client, ResultSet, Statement};
 
+/// Errors produced while serving a request, each mapped to an appropriate HTTP
+/// status so operators get actionable signals instead of a 200 with an error
+/// string embedded in the body.
+#[derive(Debug, thiserror::Error)]
+enum Error {
+    #[error("database error: {0}")]
+    Database(anyhow::Error),
+    #[error("geolocation provider error: {0}")]
+    Geolocation(anyhow::Error),
+    #[error("failed to parse upstream response as JSON: {0}")]
+    JsonParse(#[from] serde_json::Error),
+    #[error("malformed outbound HTTP request: {0}")]
+    Http(#[from] http::Error),
+    #[error("failed to render map image: {0}")]
+    Rendering(anyhow::Error),
+    #[error("invalid request parameter: {0}")]
+    BadRequest(String),
+}
+
+impl Error {
+    fn status_code(&self) -> u16 {
+        match self {
+            Error::Database(_) => 500,
+            Error::Geolocation(_) => 502,
+            Error::JsonParse(_) => 502,
+            Error::Http(_) => 400,
+            Error::Rendering(_) => 500,
+            Error::BadRequest(_) => 400,
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
 // Take a query result and render it into a HTML table
 fn result_to_html_table(result_set: ResultSet) -> Result<String> {
     let mut html = "<table style=\"border: 1px solid\">".to_string();
@@ -28,6 +61,206 @@ fn result_to_html_table(result_set: ResultSet) -> Result<String> {
     Ok(html)
 }
 
+// Escape a single field for inclusion in a CSV row, per RFC 4180: wrap in
+// quotes if it contains the delimiter, a quote, or a newline, doubling any
+// embedded quotes.
+fn escape_csv_field(value: &str, delimiter: char) -> String {
+    if value.contains(delimiter) || value.contains('"') || value.contains('\n') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// Escape a single field for inclusion in a TSV row. TSV has no standard
+// quoting convention and most readers split on every literal tab, so instead
+// of quoting, backslash-escape the characters that would otherwise be
+// misread as structure.
+fn escape_tsv_field(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+fn value_to_field(value: &libsql_client::Value) -> String {
+    match value {
+        libsql_client::Value::Text { value } => value.clone(),
+        libsql_client::Value::Integer { value } => value.to_string(),
+        libsql_client::Value::Null => String::new(),
+        _ => "(unexpected value type!)".to_string(),
+    }
+}
+
+// Take a query result and render it into a delimiter-separated table,
+// escaping each field with the given function.
+fn result_to_delimited(
+    result_set: ResultSet,
+    delimiter: char,
+    escape: impl Fn(&str) -> String,
+) -> Result<String> {
+    let mut out = String::new();
+    let header: Vec<String> = result_set.columns.iter().map(|c| escape(c)).collect();
+    out += &header.join(&delimiter.to_string());
+    out += "\r\n";
+    for row in result_set.rows {
+        let fields: Vec<String> = row
+            .values
+            .iter()
+            .map(|value| escape(&value_to_field(value)))
+            .collect();
+        out += &fields.join(&delimiter.to_string());
+        out += "\r\n";
+    }
+    Ok(out)
+}
+
+// Take a query result and render it into a CSV table
+fn result_to_csv(result_set: ResultSet) -> Result<String> {
+    result_to_delimited(result_set, ',', |field| escape_csv_field(field, ','))
+}
+
+// Take a query result and render it into a TSV table
+fn result_to_tsv(result_set: ResultSet) -> Result<String> {
+    result_to_delimited(result_set, '\t', escape_tsv_field)
+}
+
+// Take a query result and render it into a JSON array of row objects
+fn result_to_json(result_set: ResultSet) -> Result<String> {
+    let rows: Vec<serde_json::Value> = result_set
+        .rows
+        .iter()
+        .map(|row| {
+            let mut object = serde_json::Map::new();
+            for (column, value) in result_set.columns.iter().zip(row.values.iter()) {
+                let json_value = match value {
+                    libsql_client::Value::Text { value } => serde_json::Value::String(value.clone()),
+                    libsql_client::Value::Integer { value } => serde_json::Value::from(*value),
+                    libsql_client::Value::Null => serde_json::Value::Null,
+                    _ => serde_json::Value::String("(unexpected value type!)".to_string()),
+                };
+                object.insert(column.clone(), json_value);
+            }
+            serde_json::Value::Object(object)
+        })
+        .collect();
+    Ok(serde_json::to_string(&rows)?)
+}
+
+// The representation the scoreboard should be rendered in, chosen from the
+// `Accept` header or a `?format=` query parameter.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ScoreboardFormat {
+    Html,
+    Csv,
+    Tsv,
+    Json,
+}
+
+impl ScoreboardFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            ScoreboardFormat::Html => "text/html",
+            ScoreboardFormat::Csv => "text/csv",
+            ScoreboardFormat::Tsv => "text/tab-separated-values",
+            ScoreboardFormat::Json => "application/json",
+        }
+    }
+
+    fn from_hint(hint: &str) -> Option<Self> {
+        if hint.contains("application/json") || hint.contains("json") {
+            Some(ScoreboardFormat::Json)
+        } else if hint.contains("text/csv") || hint.contains("csv") {
+            Some(ScoreboardFormat::Csv)
+        } else if hint.contains("text/tab-separated-values") || hint.contains("tsv") {
+            Some(ScoreboardFormat::Tsv)
+        } else if hint.contains("text/html") || hint.contains("html") {
+            Some(ScoreboardFormat::Html)
+        } else {
+            None
+        }
+    }
+
+    // Figure out the desired format from the `?format=` query parameter first,
+    // falling back to the `Accept` header, and defaulting to HTML.
+    fn from_request(req: &Request) -> Self {
+        if let Some(format) = query_param(req, "format").and_then(|hint| Self::from_hint(&hint)) {
+            return format;
+        }
+        req.headers()
+            .get("accept")
+            .and_then(|v| v.to_str().ok())
+            .and_then(Self::from_hint)
+            .unwrap_or(ScoreboardFormat::Html)
+    }
+}
+
+// Percent-decode a query-string value: `+` becomes a space and `%XX` escapes
+// become their byte, per `application/x-www-form-urlencoded`. Query values
+// must be decoded before they're compared or used anywhere, not just before
+// they're re-encoded for an outbound request.
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            b'%' if i + 2 < bytes.len() => {
+                match std::str::from_utf8(&bytes[i + 1..i + 3])
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 3;
+                    }
+                    None => {
+                        decoded.push(bytes[i]);
+                        i += 1;
+                    }
+                }
+            }
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// Extract a single `key=value` query parameter from the request URI.
+fn query_param(req: &Request, key: &str) -> Option<String> {
+    let query = req.uri().query()?;
+    query.split('&').find_map(|pair| {
+        let (k, v) = pair.split_once('=')?;
+        (k == key).then(|| percent_decode(v))
+    })
+}
+
+// Collect every value for `key`, accepting both the plain `key=value` form and
+// the repeated `key[]=value` form some HTTP clients use for array parameters.
+fn query_params(req: &Request, key: &str) -> Vec<String> {
+    let Some(query) = req.uri().query() else {
+        return Vec::new();
+    };
+    let array_prefix = format!("{key}[]=");
+    let plain_prefix = format!("{key}=");
+    query
+        .split('&')
+        .filter_map(|pair| {
+            pair.strip_prefix(array_prefix.as_str())
+                .or_else(|| pair.strip_prefix(plain_prefix.as_str()))
+        })
+        .map(percent_decode)
+        .collect()
+}
+
 // Create a javascript canvas which loads a map of visited airports
 fn create_map_canvas(result_set: ResultSet) -> Result<String> {
     let mut canvas = r#"
@@ -73,26 +306,561 @@ fn create_map_canvas(result_set: ResultSet) -> Result<String> {
     Ok(canvas)
 }
 
+// Whether to prefer the bundled GeoLite2 database over the ip-api.com HTTP call.
+const USE_GEOLITE2: bool = false;
+const GEOLITE2_PATH: &str = "GeoLite2-City.mmdb";
+
+struct Location {
+    country: String,
+    city: String,
+    lat: f64,
+    lon: f64,
+    // A nicer, human-formatted place name filled in by a `ReverseGeocoder` or
+    // an `AddressGeocoder`, if one is configured.
+    place_name: Option<String>,
+}
+
+// The bundled GeoLite2 database, opened via `open_mmap` so the OS pages it in
+// on demand instead of reading the whole file up front. Cached in a `OnceLock`
+// so repeated lookups within the same component instance reuse it; whether
+// that spans more than one request depends on the Spin trigger's instancing
+// model. `None` means the database failed to open, in which case callers
+// fall back to the HTTP provider.
+fn geolite2_reader() -> Option<&'static maxminddb::Reader<maxminddb::Mmap>> {
+    static READER: std::sync::OnceLock<Option<maxminddb::Reader<maxminddb::Mmap>>> =
+        std::sync::OnceLock::new();
+    READER
+        .get_or_init(|| match maxminddb::Reader::open_mmap(GEOLITE2_PATH) {
+            Ok(reader) => Some(reader),
+            Err(e) => {
+                println!("failed to open GeoLite2 database: {e}");
+                None
+            }
+        })
+        .as_ref()
+}
+
+// Look up a client IP in the bundled GeoLite2 database, returning `None`
+// when the IP is private/unspecified or missing from the database, so callers
+// can fall back to the HTTP provider.
+fn geolocate_offline(client_addr: &str) -> Result<Option<Location>> {
+    let Ok(ip_addr) = client_addr.parse::<std::net::IpAddr>() else {
+        return Ok(None);
+    };
+    if ip_addr.is_unspecified() || ip_addr.is_loopback() {
+        return Ok(None);
+    }
+    if let std::net::IpAddr::V4(v4) = ip_addr {
+        if v4.is_private() {
+            return Ok(None);
+        }
+    }
+
+    let Some(reader) = geolite2_reader() else {
+        return Ok(None);
+    };
+    let city: maxminddb::geoip2::City = match reader.lookup(ip_addr) {
+        Ok(city) => city,
+        Err(_) => return Ok(None),
+    };
+
+    let country = city
+        .country
+        .as_ref()
+        .and_then(|c| c.names.as_ref())
+        .and_then(|names| names.get("en"))
+        .map(|s| s.to_string());
+    let city_name = city
+        .city
+        .as_ref()
+        .and_then(|c| c.names.as_ref())
+        .and_then(|names| names.get("en"))
+        .map(|s| s.to_string());
+    let (lat, lon) = city
+        .location
+        .as_ref()
+        .map(|loc| {
+            (
+                loc.latitude.unwrap_or_default(),
+                loc.longitude.unwrap_or_default(),
+            )
+        })
+        .unwrap_or_default();
+
+    Ok(match (country, city_name) {
+        (Some(country), Some(city)) => Some(Location {
+            country,
+            city,
+            lat,
+            lon,
+            place_name: None,
+        }),
+        _ => None,
+    })
+}
+
+// A pluggable IP-based location provider.
+trait Geocoder {
+    fn locate(&self, ip: &str) -> Result<Location>;
+}
+
+// A provider that turns coordinates back into a human-readable place name.
+trait ReverseGeocoder {
+    fn reverse(&self, lat: f64, lon: f64) -> Result<String>;
+}
+
+// Looks up a client IP via the ip-api.com HTTP geolocation service.
+struct IpApi;
+
+impl Geocoder for IpApi {
+    fn locate(&self, ip: &str) -> Result<Location> {
+        let req = http::Request::builder()
+            .uri(format!("http://ip-api.com/json/{ip}?fields=country,city,lat,lon"));
+        let geo = spin_sdk::outbound_http::send_request(req.body(None)?)
+            .map_err(Error::Geolocation)?;
+        let geo = geo.into_body().expect("Received empty geolocation data");
+        let geo = std::str::from_utf8(&geo).map_err(|e| Error::Geolocation(e.into()))?;
+        let geo: serde_json::Value = serde_json::from_str(geo)?;
+
+        Ok(Location {
+            country: geo["country"]
+                .as_str()
+                .unwrap_or("[undisclosed]")
+                .to_string(),
+            city: geo["city"]
+                .as_str()
+                .unwrap_or("Secret Turso HQ")
+                .to_string(),
+            lat: geo["lat"].as_f64().unwrap_or_default(),
+            lon: geo["lon"].as_f64().unwrap_or_default(),
+            place_name: None,
+        })
+    }
+}
+
+// Reverse-geocodes coordinates into a formatted place name via OpenCage.
+// OpenCage's free tier enforces 1 request/second and a daily quota;
+// `remaining_quota` tracks what the last response reported so callers can
+// stop before exhausting it.
+struct OpenCage {
+    api_key: String,
+    remaining_quota: std::cell::Cell<Option<u32>>,
+}
+
+impl OpenCage {
+    fn new(api_key: impl Into<String>) -> Self {
+        OpenCage {
+            api_key: api_key.into(),
+            remaining_quota: std::cell::Cell::new(None),
+        }
+    }
+
+    fn remaining_quota(&self) -> Option<u32> {
+        self.remaining_quota.get()
+    }
+}
+
+impl OpenCage {
+    // Run a geocode/v1/json query, tracking the quota the response reported
+    // and falling through to an error on rate-limit/quota responses so
+    // callers can move on to the next configured provider.
+    fn query(&self, q: &str) -> Result<serde_json::Value> {
+        let req = http::Request::builder().uri(format!(
+            "https://api.opencagedata.com/geocode/v1/json?q={q}&key={}&no_annotations=1",
+            self.api_key
+        ));
+        let res = spin_sdk::outbound_http::send_request(req.body(None)?)
+            .map_err(Error::Geolocation)?;
+        let body = res.into_body().expect("Received empty OpenCage response");
+        let body = std::str::from_utf8(&body).map_err(|e| Error::Geolocation(e.into()))?;
+        let body: serde_json::Value = serde_json::from_str(body)?;
+
+        let remaining = body["rate"]["remaining"].as_u64().map(|v| v as u32);
+        self.remaining_quota.set(remaining);
+        println!("OpenCage quota remaining: {remaining:?}");
+
+        if remaining == Some(0) {
+            return Err(Error::Geolocation(anyhow::anyhow!(
+                "OpenCage daily quota exhausted"
+            )));
+        }
+        let status = body["status"]["code"].as_i64().unwrap_or(200);
+        if status == 402 || status == 429 {
+            return Err(Error::Geolocation(anyhow::anyhow!(
+                "OpenCage rate limit exceeded (status {status})"
+            )));
+        }
+
+        Ok(body)
+    }
+}
+
+impl ReverseGeocoder for OpenCage {
+    fn reverse(&self, lat: f64, lon: f64) -> Result<String> {
+        // OpenCage's reverse-geocoding fast path expects `lat,lng`, not `+`-joined.
+        let body = self.query(&format!("{lat},{lon}"))?;
+        body["results"][0]["formatted"]
+            .as_str()
+            .map(|s| s.to_string())
+            .ok_or_else(|| Error::Geolocation(anyhow::anyhow!("OpenCage returned no results")))
+    }
+}
+
+// A provider that turns a free-form address into coordinates.
+trait AddressGeocoder {
+    fn geocode(&self, address: &str) -> Result<Location>;
+}
+
+// Percent-encode a string for safe interpolation into a URI query component,
+// per RFC 3986 (unreserved characters pass through as-is, everything else
+// becomes `%XX`).
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char);
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+impl AddressGeocoder for OpenCage {
+    fn geocode(&self, address: &str) -> Result<Location> {
+        let body = self.query(&percent_encode(address))?;
+        let result = body["results"].get(0).ok_or_else(|| {
+            Error::Geolocation(anyhow::anyhow!("OpenCage found no results for address"))
+        })?;
+
+        Ok(Location {
+            country: result["components"]["country"]
+                .as_str()
+                .unwrap_or("[undisclosed]")
+                .to_string(),
+            city: result["components"]["city"]
+                .as_str()
+                .or_else(|| result["components"]["town"].as_str())
+                .or_else(|| result["components"]["village"].as_str())
+                .unwrap_or("Secret Turso HQ")
+                .to_string(),
+            lat: result["geometry"]["lat"].as_f64().unwrap_or_default(),
+            lon: result["geometry"]["lng"].as_f64().unwrap_or_default(),
+            place_name: result["formatted"].as_str().map(|s| s.to_string()),
+        })
+    }
+}
+
+// Disabled by default, same as `USE_GEOLITE2` above: set an API key to enable
+// place-name enrichment and address/city lookups.
+const OPENCAGE_API_KEY: Option<&str> = None;
+
+// Try each geocoder in order, falling through to the next on failure.
+fn geocode_with_fallback(providers: &[Box<dyn Geocoder>], ip: &str) -> Result<Location> {
+    let mut last_err = None;
+    for provider in providers {
+        match provider.locate(ip) {
+            Ok(location) => return Ok(location),
+            Err(e) => {
+                println!("geocoder provider failed, trying next: {e}");
+                last_err = Some(e);
+            }
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        Error::Geolocation(anyhow::anyhow!("no geocoding providers configured"))
+    }))
+}
+
+// Fill in `location.place_name` using the first reverse geocoder that succeeds,
+// leaving it unset (rather than failing the request) if all of them do not.
+fn enrich_with_place_name(location: &mut Location, reverse_geocoders: &[Box<dyn ReverseGeocoder>]) {
+    for reverse_geocoder in reverse_geocoders {
+        match reverse_geocoder.reverse(location.lat, location.lon) {
+            Ok(place_name) => {
+                location.place_name = Some(place_name);
+                return;
+            }
+            Err(e) => println!("reverse geocoder failed, trying next: {e}"),
+        }
+    }
+}
+
+// Resolve a client's location, preferring the bundled GeoLite2 database when
+// enabled and falling back to an ordered list of online `Geocoder` providers
+// otherwise.
+fn geolocate(client_addr: &str) -> Result<Location> {
+    if USE_GEOLITE2 {
+        if let Some(location) = geolocate_offline(client_addr)? {
+            return Ok(location);
+        }
+    }
+
+    let providers: Vec<Box<dyn Geocoder>> = vec![Box::new(IpApi)];
+    let mut location = geocode_with_fallback(&providers, client_addr)?;
+
+    if let Some(api_key) = OPENCAGE_API_KEY {
+        let opencage = OpenCage::new(api_key);
+        let reverse_geocoders: Vec<Box<dyn ReverseGeocoder>> = vec![Box::new(opencage)];
+        enrich_with_place_name(&mut location, &reverse_geocoders);
+    }
+
+    Ok(location)
+}
+
+// Resolve the location to count a request against: an explicit `?address=`
+// takes priority, then an explicit `?lat=&lon=` pair, falling back to
+// geolocating the caller's own IP.
+fn resolve_location(req: &Request, client_addr: &str) -> Result<Location> {
+    if let Some(address) = query_param(req, "address") {
+        let api_key = OPENCAGE_API_KEY.ok_or_else(|| {
+            Error::BadRequest("?address= requires an OpenCage API key to be configured".to_string())
+        })?;
+        return OpenCage::new(api_key).geocode(&address);
+    }
+
+    if let (Some(lat), Some(lon)) = (query_param(req, "lat"), query_param(req, "lon")) {
+        let lat: f64 = lat
+            .parse()
+            .map_err(|_| Error::BadRequest(format!("invalid ?lat= value: {lat}")))?;
+        let lon: f64 = lon
+            .parse()
+            .map_err(|_| Error::BadRequest(format!("invalid ?lon= value: {lon}")))?;
+        return Ok(Location {
+            country: "[undisclosed]".to_string(),
+            city: "[undisclosed]".to_string(),
+            lat,
+            lon,
+            place_name: None,
+        });
+    }
+
+    geolocate(client_addr)
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+// Take the coordinates table and render it into a GPX 1.1 waypoint document
+fn result_to_gpx(result_set: ResultSet) -> Result<String> {
+    let mut gpx = String::from(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx version="1.1" creator="country-counter-spin" xmlns="http://www.topografix.com/GPX/1/1">
+  <metadata>
+    <name>Visited airports</name>
+  </metadata>
+"#,
+    );
+    for row in result_set.rows {
+        let lat = &row.value_map["lat"];
+        let lon = &row.value_map["long"];
+        let airport = value_to_field(&row.value_map["airport"]);
+        gpx += &format!(
+            "  <wpt lat=\"{lat}\" lon=\"{lon}\"><name>{}</name></wpt>\n",
+            xml_escape(&airport)
+        );
+    }
+    gpx += "</gpx>\n";
+    Ok(gpx)
+}
+
+// Serve the GPX export of visited coordinates, optionally scoped to `countries`
+fn serve_gpx(db: Client, countries: &[&str]) -> Result<String> {
+    db.execute(
+        "CREATE TABLE IF NOT EXISTS coordinates(lat INT, long INT, airport TEXT, country TEXT, PRIMARY KEY (lat, long))",
+    )
+    .map_err(Error::Database)?;
+    let coords = db
+        .execute(coordinates_query(countries))
+        .map_err(Error::Database)?;
+    result_to_gpx(coords)
+}
+
+const MAP_WIDTH: u32 = 800;
+const MAP_HEIGHT: u32 = 400;
+const MAP_BACKGROUND: image::Rgba<u8> = image::Rgba([30, 60, 110, 255]);
+const MAP_LAND_COLOR: image::Rgba<u8> = image::Rgba([60, 110, 70, 255]);
+const MAP_MARKER_COLOR: image::Rgba<u8> = image::Rgba([235, 94, 52, 255]);
+const MAP_MARKER_RADIUS: i64 = 3;
+
+// Coarse (lat, lon) polygon outlines of the continents, just precise enough to
+// give the rendered PNG a geographic reference without bundling a raster
+// basemap asset into the source tree.
+const CONTINENTS: &[&[(f64, f64)]] = &[
+    // North America
+    &[
+        (71.0, -156.0), (60.0, -140.0), (49.0, -125.0), (32.0, -117.0),
+        (15.0, -95.0), (10.0, -85.0), (25.0, -80.0), (45.0, -65.0),
+        (60.0, -65.0), (71.0, -100.0),
+    ],
+    // South America
+    &[
+        (12.0, -72.0), (5.0, -77.0), (-18.0, -70.0), (-55.0, -68.0),
+        (-38.0, -58.0), (-5.0, -35.0), (5.0, -50.0), (10.0, -62.0),
+    ],
+    // Africa + Europe + Asia
+    &[
+        (71.0, 30.0), (60.0, 60.0), (40.0, 135.0), (10.0, 105.0),
+        (-10.0, 40.0), (-35.0, 20.0), (0.0, -17.0), (15.0, -17.0),
+        (36.0, -10.0), (45.0, 10.0), (60.0, 5.0),
+    ],
+    // Australia
+    &[
+        (-11.0, 130.0), (-12.0, 142.0), (-25.0, 153.0), (-38.0, 145.0),
+        (-35.0, 117.0), (-20.0, 114.0),
+    ],
+];
+
+fn value_to_f64(value: &libsql_client::Value) -> f64 {
+    value.to_string().parse().unwrap_or_default()
+}
+
+// Project a (lat, lon) pair onto pixel space with an equirectangular projection.
+fn project_to_pixel(lat: f64, lon: f64, width: u32, height: u32) -> (u32, u32) {
+    let x = (lon + 180.0) / 360.0 * width as f64;
+    let y = (90.0 - lat) / 180.0 * height as f64;
+    (
+        (x as i64).clamp(0, width as i64 - 1) as u32,
+        (y as i64).clamp(0, height as i64 - 1) as u32,
+    )
+}
+
+// Ray-casting point-in-polygon test over pixel-space vertices.
+fn point_in_polygon(x: f64, y: f64, polygon: &[(f64, f64)]) -> bool {
+    let mut inside = false;
+    for i in 0..polygon.len() {
+        let (xi, yi) = polygon[i];
+        let (xj, yj) = polygon[(i + polygon.len() - 1) % polygon.len()];
+        if (yi > y) != (yj > y) && x < (xj - xi) * (y - yi) / (yj - yi) + xi {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+// Fill in the continent outlines so plotted points land on a recognizable map
+// instead of a flat ocean color.
+fn draw_base_map(image: &mut image::RgbaImage) {
+    let (width, height) = image.dimensions();
+    for continent in CONTINENTS {
+        let polygon: Vec<(f64, f64)> = continent
+            .iter()
+            .map(|&(lat, lon)| {
+                let (x, y) = project_to_pixel(lat, lon, width, height);
+                (x as f64, y as f64)
+            })
+            .collect();
+        for y in 0..height {
+            for x in 0..width {
+                if point_in_polygon(x as f64, y as f64, &polygon) {
+                    image.put_pixel(x, y, MAP_LAND_COLOR);
+                }
+            }
+        }
+    }
+}
+
+// Stamp a small filled circular marker centered at (x, y).
+fn stamp_marker(image: &mut image::RgbaImage, x: u32, y: u32) {
+    let (width, height) = image.dimensions();
+    for dy in -MAP_MARKER_RADIUS..=MAP_MARKER_RADIUS {
+        for dx in -MAP_MARKER_RADIUS..=MAP_MARKER_RADIUS {
+            if dx * dx + dy * dy > MAP_MARKER_RADIUS * MAP_MARKER_RADIUS {
+                continue;
+            }
+            let px = x as i64 + dx;
+            let py = y as i64 + dy;
+            if px >= 0 && py >= 0 && (px as u32) < width && (py as u32) < height {
+                image.put_pixel(px as u32, py as u32, MAP_MARKER_COLOR);
+            }
+        }
+    }
+}
+
+// Take the coordinates table and render it into a PNG of visited airports
+// plotted over a world map
+fn result_to_png(result_set: ResultSet) -> Result<Vec<u8>> {
+    let mut image = image::RgbaImage::from_pixel(MAP_WIDTH, MAP_HEIGHT, MAP_BACKGROUND);
+    draw_base_map(&mut image);
+    for row in result_set.rows {
+        let lat = value_to_f64(&row.value_map["lat"]);
+        let lon = value_to_f64(&row.value_map["long"]);
+        let (x, y) = project_to_pixel(lat, lon, MAP_WIDTH, MAP_HEIGHT);
+        stamp_marker(&mut image, x, y);
+    }
+
+    let mut png = Vec::new();
+    image::DynamicImage::ImageRgba8(image)
+        .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+        .map_err(|e| Error::Rendering(e.into()))?;
+    Ok(png)
+}
+
+// Serve the static PNG rendering of visited coordinates, optionally scoped to `countries`
+fn serve_png(db: Client, countries: &[&str]) -> Result<Vec<u8>> {
+    db.execute(
+        "CREATE TABLE IF NOT EXISTS coordinates(lat INT, long INT, airport TEXT, country TEXT, PRIMARY KEY (lat, long))",
+    )
+    .map_err(Error::Database)?;
+    let coords = db
+        .execute(coordinates_query(countries))
+        .map_err(Error::Database)?;
+    result_to_png(coords)
+}
+
+// Build a `SELECT * FROM counter` query, optionally scoped to a set of
+// countries via a parameterized `IN (...)` clause.
+fn counter_query(countries: &[&str]) -> Statement {
+    if countries.is_empty() {
+        return Statement::with_args("SELECT * FROM counter", countries);
+    }
+    let placeholders = vec!["?"; countries.len()].join(",");
+    Statement::with_args(
+        format!("SELECT * FROM counter WHERE country IN ({placeholders})"),
+        countries,
+    )
+}
+
+// Build the coordinates query for the map, optionally scoped to a set of
+// countries via the `country` column stored alongside each coordinate.
+fn coordinates_query(countries: &[&str]) -> Statement {
+    if countries.is_empty() {
+        return Statement::with_args("SELECT airport, lat, long FROM coordinates", countries);
+    }
+    let placeholders = vec!["?"; countries.len()].join(",");
+    Statement::with_args(
+        format!("SELECT airport, lat, long FROM coordinates WHERE country IN ({placeholders})"),
+        countries,
+    )
+}
+
 // Serve a request to load the page
-fn serve(db: Client, client_addr: &str) -> Result<String> {
+fn serve(
+    db: Client,
+    format: ScoreboardFormat,
+    location: Location,
+    countries: &[&str],
+) -> Result<String> {
     // Recreate the tables if they do not exist yet
-    db.execute("CREATE TABLE IF NOT EXISTS counter(country TEXT, city TEXT, value, PRIMARY KEY(country, city)) WITHOUT ROWID")?;
+    db.execute("CREATE TABLE IF NOT EXISTS counter(country TEXT, city TEXT, value, PRIMARY KEY(country, city)) WITHOUT ROWID")
+        .map_err(Error::Database)?;
     db.execute(
-        "CREATE TABLE IF NOT EXISTS coordinates(lat INT, long INT, airport TEXT, PRIMARY KEY (lat, long))",
-    )?;
-
-    let req = http::Request::builder().uri(format!(
-        "http://ip-api.com/json/{client_addr}?fields=country,city,lat,lon"
-    ));
-    let geo = spin_sdk::outbound_http::send_request(req.body(None)?)?;
-    let geo = geo.into_body().expect("Received empty geolocation data");
-    let geo: serde_json::Value = serde_json::from_str(std::str::from_utf8(&geo)?)?;
-
-    let city = geo["city"].as_str().unwrap_or("Secret Turso HQ");
-    let country = geo["country"].as_str().unwrap_or("[undisclosed]");
-    let airport = city;
-    let latitude = geo["lat"].as_f64().unwrap_or_default();
-    let longitude = geo["lon"].as_f64().unwrap_or_default();
+        "CREATE TABLE IF NOT EXISTS coordinates(lat INT, long INT, airport TEXT, country TEXT, PRIMARY KEY (lat, long))",
+    )
+    .map_err(Error::Database)?;
+
+    let city = location.city.as_str();
+    let country = location.country.as_str();
+    let airport = location.place_name.as_deref().unwrap_or(city);
+    let latitude = location.lat;
+    let longitude = location.lon;
 
     db.batch([
         Statement::with_args(
@@ -104,15 +872,28 @@ fn serve(db: Client, client_addr: &str) -> Result<String> {
             &[country, city],
         ),
         Statement::with_args(
-            "INSERT OR IGNORE INTO coordinates VALUES (?, ?, ?)",
-            args!(latitude, longitude, airport),
+            "INSERT OR IGNORE INTO coordinates VALUES (?, ?, ?, ?)",
+            args!(latitude, longitude, airport, country),
         ),
-    ])?;
+    ])
+    .map_err(Error::Database)?;
 
-    let counter_response = db.execute("SELECT * FROM counter")?;
+    let counter_response = db
+        .execute(counter_query(countries))
+        .map_err(Error::Database)?;
+    if format != ScoreboardFormat::Html {
+        return match format {
+            ScoreboardFormat::Csv => result_to_csv(counter_response),
+            ScoreboardFormat::Tsv => result_to_tsv(counter_response),
+            ScoreboardFormat::Json => result_to_json(counter_response),
+            ScoreboardFormat::Html => unreachable!(),
+        };
+    }
     let scoreboard = result_to_html_table(counter_response)?;
 
-    let coords = db.execute("SELECT airport, lat, long FROM coordinates")?;
+    let coords = db
+        .execute(coordinates_query(countries))
+        .map_err(Error::Database)?;
     let canvas = create_map_canvas(coords)?;
     let html = format!(
         r#"
@@ -131,7 +912,7 @@ fn serve(db: Client, client_addr: &str) -> Result<String> {
 
 /// A simple Spin HTTP component.
 #[http_component]
-fn handle_country_counter_spin(req: Request) -> Result<Response> {
+fn handle_country_counter_spin(req: Request) -> anyhow::Result<Response> {
     println!("{:?}", req.uri());
     println!("{:?}", req.headers());
     println!(
@@ -157,12 +938,53 @@ fn handle_country_counter_spin(req: Request) -> Result<Response> {
     )
     .unwrap();
 
-    let html = match serve(db, &client_addr) {
-        Ok(html) => html,
-        Err(e) => format!("{e}"),
-    };
+    let countries = query_params(&req, "country");
+    let countries: Vec<&str> = countries.iter().map(String::as_str).collect();
+
+    if query_param(&req, "export").as_deref() == Some("gpx") {
+        return Ok(match serve_gpx(db, &countries) {
+            Ok(body) => http::Response::builder()
+                .status(200)
+                .header("content-type", "application/gpx+xml")
+                .header(
+                    "content-disposition",
+                    "attachment; filename=\"visited-airports.gpx\"",
+                )
+                .body(Some(body.into()))?,
+            Err(e) => error_response(e)?,
+        });
+    }
+
+    if query_param(&req, "export").as_deref() == Some("png") {
+        return Ok(match serve_png(db, &countries) {
+            Ok(body) => http::Response::builder()
+                .status(200)
+                .header("content-type", "image/png")
+                .body(Some(body.into()))?,
+            Err(e) => error_response(e)?,
+        });
+    }
+
+    let format = ScoreboardFormat::from_request(&req);
+
+    Ok(match resolve_location(&req, &client_addr)
+        .and_then(|location| serve(db, format, location, &countries))
+    {
+        Ok(body) => http::Response::builder()
+            .status(200)
+            .header("content-type", format.content_type())
+            .body(Some(body.into()))?,
+        Err(e) => error_response(e)?,
+    })
+}
 
+// Render a clean error body with the status code appropriate for the failure,
+// instead of collapsing every error into a 200 with the message in the HTML.
+fn error_response(e: Error) -> anyhow::Result<Response> {
+    let status = e.status_code();
+    println!("request failed with status {status}: {e}");
     Ok(http::Response::builder()
-        .status(200)
-        .body(Some(html.into()))?)
+        .status(status)
+        .header("content-type", "text/plain")
+        .body(Some(e.to_string().into()))?)
 }